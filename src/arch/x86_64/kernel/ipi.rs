@@ -0,0 +1,67 @@
+use alloc::collections::VecDeque;
+use core::task::Waker;
+
+use hermit_sync::InterruptTicketMutex;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+use super::apic;
+use super::percore::core_id;
+use crate::config::MAX_CORES;
+
+/// Interrupt vector used to pull a remote core out of `hlt` when a waker
+/// belonging to one of its tasks fires while it is parked.
+///
+/// Each core owns a mailbox of pending wakers; a waker fired from another
+/// core is enqueued there and this IPI is sent to wake the target core out
+/// of `hlt` so it can drain its mailbox.
+pub const WAKEUP_IPI_VECTOR: u8 = 0x82;
+
+struct Mailbox {
+	queue: InterruptTicketMutex<VecDeque<Waker>>,
+}
+
+impl Mailbox {
+	const fn new() -> Self {
+		Self {
+			queue: InterruptTicketMutex::new(VecDeque::new()),
+		}
+	}
+}
+
+static MAILBOXES: [Mailbox; MAX_CORES] = [const { Mailbox::new() }; MAX_CORES];
+
+/// Wake `waker`, which belongs to a task living on `target_core`.
+///
+/// If `target_core` is the current core, the waker is woken in place, same
+/// as before this mechanism existed. Otherwise it is enqueued in that
+/// core's mailbox and `target_core` is sent [`WAKEUP_IPI_VECTOR`] so it is
+/// pulled out of `hlt` (if it was parked there) and drains its mailbox.
+pub(crate) fn wake_on_core(target_core: usize, waker: Waker) {
+	if target_core == core_id() {
+		waker.wake();
+		return;
+	}
+
+	MAILBOXES[target_core].queue.lock().push_back(waker);
+	apic::send_ipi(target_core, WAKEUP_IPI_VECTOR);
+}
+
+/// Handler for [`WAKEUP_IPI_VECTOR`]: drains the current core's mailbox,
+/// waking every queued task so the executor re-polls it on its next
+/// iteration.
+pub(crate) extern "x86-interrupt" fn wakeup_handler(_stack_frame: InterruptStackFrame) {
+	apic::eoi();
+
+	let mut queue = MAILBOXES[core_id()].queue.lock();
+	while let Some(waker) = queue.pop_front() {
+		waker.wake();
+	}
+}
+
+/// Install the [`WAKEUP_IPI_VECTOR`] handler into `idt`.
+///
+/// Called once per core during interrupt setup, alongside the other
+/// exception and device IRQ handlers.
+pub(crate) fn install(idt: &mut InterruptDescriptorTable) {
+	idt[usize::from(WAKEUP_IPI_VECTOR)].set_handler_fn(wakeup_handler);
+}
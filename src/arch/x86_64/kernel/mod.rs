@@ -0,0 +1,2 @@
+pub(crate) mod gdt;
+pub(crate) mod ipi;
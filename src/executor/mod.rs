@@ -0,0 +1,87 @@
+//! Per-core cooperative task executor.
+//!
+//! Each core owns its own run queue, indexed by `core_id()`; there is no
+//! work stealing. A task is only re-polled once its waker fires, so an idle
+//! core can safely fall back to [`park`](park::park) instead of re-polling
+//! in a tight loop.
+
+mod park;
+mod vsock;
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Waker};
+
+use hermit_sync::InterruptTicketMutex;
+
+use crate::arch::kernel::interrupts;
+use crate::arch::kernel::percore::core_id;
+use crate::config::MAX_CORES;
+
+struct Task {
+	/// The core this task was spawned on. Tasks never migrate, so this is
+	/// also the only core that ever polls it or re-queues it on wake.
+	core_id: usize,
+	future: InterruptTicketMutex<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+impl Wake for Task {
+	fn wake(self: Arc<Self>) {
+		self.wake_by_ref();
+	}
+
+	fn wake_by_ref(self: &Arc<Self>) {
+		READY_QUEUES[self.core_id].lock().push_back(self.clone());
+	}
+}
+
+static READY_QUEUES: [InterruptTicketMutex<VecDeque<Arc<Task>>>; MAX_CORES] =
+	[const { InterruptTicketMutex::new(VecDeque::new()) }; MAX_CORES];
+
+/// Spawn `future` onto the current core's run queue.
+pub(crate) fn spawn(future: impl Future<Output = ()> + Send + 'static) {
+	let task = Arc::new(Task {
+		core_id: core_id(),
+		future: InterruptTicketMutex::new(Box::pin(future)),
+	});
+	READY_QUEUES[task.core_id].lock().push_back(task);
+}
+
+/// Poll every task currently ready on this core once, without sleeping.
+///
+/// Call this whenever the run queue might hold runnable work; each task
+/// polled here re-queues itself (via its waker) only once something wakes
+/// it again, so this never spins on a task that is genuinely waiting on a
+/// device.
+pub(crate) fn poll_io() {
+	let ready: VecDeque<Arc<Task>> = core::mem::take(&mut *READY_QUEUES[core_id()].lock());
+	for task in ready {
+		let waker = Waker::from(task.clone());
+		let mut cx = Context::from_waker(&waker);
+		let _ = task.future.lock().as_mut().poll(&mut cx);
+	}
+}
+
+/// Run the idle loop for the current core: service ready tasks, and park
+/// once there is nothing left to run.
+pub(crate) fn run() -> ! {
+	loop {
+		poll_io();
+
+		// Interrupts stay disabled from here straight through to the
+		// atomic `sti; hlt` in `park`, so a device IRQ that enqueues work
+		// after this emptiness check is latched and delivered right after
+		// the `sti` instead of being lost while we were still deciding
+		// whether to park.
+		interrupts::disable();
+		if READY_QUEUES[core_id()].lock().is_empty() {
+			park::park();
+		} else {
+			interrupts::enable();
+		}
+	}
+}
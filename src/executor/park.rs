@@ -0,0 +1,16 @@
+//! Parking primitive for an idle core.
+
+use crate::arch::kernel::interrupts;
+
+/// Park the current core until a device interrupt (or timer) wakes it.
+///
+/// The caller must already have interrupts disabled and must not have
+/// re-enabled them since observing the run queue empty. `enable_and_hlt`
+/// then re-enables and halts as a single, uninterruptible instruction
+/// pair, so an interrupt raised any time after interrupts were disabled
+/// (including between the emptiness check and this call) stays latched
+/// and is delivered right after the `sti` instead of being lost while the
+/// core was still deciding whether to park.
+pub(crate) fn park() {
+	interrupts::enable_and_hlt();
+}
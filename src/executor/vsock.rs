@@ -1,19 +1,21 @@
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, VecDeque};
 use alloc::vec::Vec;
 use core::future;
 use core::task::{Poll, Waker};
 
-use endian_num::{le16, le32};
+use endian_num::{le16, le32, le64};
 use hermit_sync::InterruptTicketMutex;
 use virtio::vsock::{Hdr, Op, Type};
 
+use crate::arch::kernel::ipi;
 #[cfg(not(feature = "pci"))]
 use crate::arch::kernel::mmio as hardware;
+use crate::arch::kernel::percore::core_id;
 #[cfg(feature = "pci")]
 use crate::drivers::pci as hardware;
 use crate::executor::spawn;
 use crate::io;
-use crate::io::Error::EADDRINUSE;
+use crate::io::Error::{EADDRINUSE, ECONNREFUSED, EIO};
 
 pub(crate) static VSOCK_MAP: InterruptTicketMutex<VsockMap> =
 	InterruptTicketMutex::new(VsockMap::new());
@@ -32,11 +34,17 @@ pub(crate) enum VsockState {
 #[derive(Debug)]
 pub(crate) struct WakerRegistration {
 	waker: Option<Waker>,
+	/// The core the registered waker's task was running on, so `wake` can
+	/// route through the cross-core IPI mechanism when needed.
+	core_id: usize,
 }
 
 impl WakerRegistration {
 	pub const fn new() -> Self {
-		Self { waker: None }
+		Self {
+			waker: None,
+			core_id: 0,
+		}
 	}
 
 	/// Register a waker. Overwrites the previous waker, if any.
@@ -49,39 +57,108 @@ impl WakerRegistration {
 			// - we have no waker registered
 			// - we have a waker registered but it's for a different task.
 			// then clone the new waker and store it
-			_ => self.waker = Some(w.clone()),
+			_ => {
+				self.waker = Some(w.clone());
+				self.core_id = core_id();
+			}
 		}
 	}
 
-	/// Wake the registered waker, if any.
+	/// Wake the registered waker, if any. Dispatched through `ipi::wake_on_core`
+	/// so a task parked on another core is actually pulled out of `hlt`.
 	pub fn wake(&mut self) {
 		if let Some(w) = self.waker.take() {
-			w.wake()
+			ipi::wake_on_core(self.core_id, w);
 		}
 	}
 }
 
 pub(crate) const RAW_SOCKET_BUFFER_SIZE: usize = 256 * 1024;
 
+/// Bytes received from the peer but not yet consumed by the application.
+///
+/// `Type::Stream` sockets are a plain byte pipe, so incoming payloads are
+/// simply appended to a flat buffer. `Type::SeqPacket` sockets must instead
+/// preserve message boundaries, so each incoming `Op::Rw` payload is kept as
+/// its own record and handed back to the application one datagram per read.
+#[derive(Debug)]
+pub(crate) enum RecvBuffer {
+	Stream(Vec<u8>),
+	SeqPacket(VecDeque<Vec<u8>>),
+}
+
+impl RecvBuffer {
+	fn new(socket_type: Type) -> Self {
+		match socket_type {
+			Type::SeqPacket => Self::SeqPacket(VecDeque::new()),
+			_ => Self::Stream(Vec::with_capacity(RAW_SOCKET_BUFFER_SIZE)),
+		}
+	}
+
+	/// Append an incoming `Op::Rw` payload.
+	fn push(&mut self, data: &[u8]) {
+		match self {
+			Self::Stream(buf) => buf.extend_from_slice(data),
+			Self::SeqPacket(records) => records.push_back(data.to_vec()),
+		}
+	}
+}
+
 #[derive(Debug)]
 pub(crate) struct RawSocket {
+	pub local_cid: u32,
 	pub remote_cid: u32,
 	pub remote_port: u32,
+	pub socket_type: Type,
 	pub state: VsockState,
 	pub waker: WakerRegistration,
-	pub buffer: Vec<u8>,
+	/// Woken up whenever the peer's credit window might have grown, so a
+	/// writer parked in [`send`] on an exhausted [`RawSocket::peer_free`]
+	/// window can retry.
+	pub tx_waker: WakerRegistration,
+	pub buffer: RecvBuffer,
+	/// Size of the peer's receive buffer, as last advertised in `buf_alloc`.
+	pub peer_buf_alloc: u32,
+	/// Total bytes the peer has forwarded to its application, as last
+	/// advertised in `fwd_cnt`.
+	pub peer_fwd_cnt: u32,
+	/// Total bytes we have sent to the peer so far.
+	pub tx_cnt: u32,
+	/// Total bytes we have delivered to the application so far. Reported to
+	/// the peer as our own `fwd_cnt`.
+	pub rx_cnt: u32,
 }
 
 impl RawSocket {
-	pub fn new(state: VsockState) -> Self {
+	pub fn new(state: VsockState, socket_type: Type) -> Self {
 		Self {
+			local_cid: 0,
 			remote_cid: 0,
 			remote_port: 0,
+			socket_type,
 			state,
 			waker: WakerRegistration::new(),
-			buffer: Vec::with_capacity(RAW_SOCKET_BUFFER_SIZE),
+			tx_waker: WakerRegistration::new(),
+			buffer: RecvBuffer::new(socket_type),
+			peer_buf_alloc: 0,
+			peer_fwd_cnt: 0,
+			tx_cnt: 0,
+			rx_cnt: 0,
 		}
 	}
+
+	/// Number of bytes we are currently still allowed to send to the peer
+	/// without exceeding the receive window it last advertised.
+	pub fn peer_free(&self) -> u32 {
+		self.peer_buf_alloc
+			.saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt))
+	}
+
+	/// Mark `len` bytes as delivered to the application, e.g. after the
+	/// application has drained them out of `buffer`.
+	pub fn ack_read(&mut self, len: usize) {
+		self.rx_cnt = self.rx_cnt.wrapping_add(len.try_into().unwrap());
+	}
 }
 
 async fn vsock_run() {
@@ -99,27 +176,61 @@ async fn vsock_run() {
 				let mut vsock_guard = VSOCK_MAP.lock();
 
 				if let Some(raw) = vsock_guard.get_mut_socket(port) {
-					if op == Op::Request && raw.state == VsockState::Listen && type_ == Type::Stream
+					// The peer's credit window is carried in every header, not
+					// just in dedicated CreditUpdate/CreditRequest packets, so
+					// a parked writer must be woken here whenever it grows,
+					// regardless of which op piggybacked it.
+					let peer_free_before = raw.peer_free();
+					raw.peer_buf_alloc = header.buf_alloc.to_ne();
+					raw.peer_fwd_cnt = header.fwd_cnt.to_ne();
+					if raw.peer_free() > peer_free_before {
+						raw.tx_waker.wake();
+					}
+
+					if op == Op::Request
+						&& raw.state == VsockState::Listen
+						&& type_ == raw.socket_type
 					{
 						raw.state = VsockState::ReceiveRequest;
+						raw.local_cid = header.dst_cid.to_ne().try_into().unwrap();
 						raw.remote_cid = header.src_cid.to_ne().try_into().unwrap();
 						raw.remote_port = header.src_port.to_ne();
 						raw.waker.wake();
+					} else if op == Op::Request && raw.state == VsockState::Listen {
+						// The peer asked for a different socket type than the one
+						// bound on this port.
+						hdr = Some(*header);
+					} else if raw.state == VsockState::Connecting
+						&& op == Op::Response
+						&& type_ == raw.socket_type
+					{
+						raw.state = VsockState::Connected;
+						raw.waker.wake();
+					} else if raw.state == VsockState::Connecting
+						&& (op == Op::Rst || op == Op::Response)
+					{
+						// Either the peer refused our connection attempt, or it
+						// answered with a different socket type than we asked
+						// for; either way the connection attempt has failed.
+						raw.state = VsockState::Shutdown;
+						raw.waker.wake();
 					} else if (raw.state == VsockState::Connected
 						|| raw.state == VsockState::Shutdown)
-						&& type_ == Type::Stream
+						&& type_ == raw.socket_type
 						&& op == Op::Rw
 					{
-						raw.buffer.extend_from_slice(data);
+						raw.buffer.push(data);
 						raw.waker.wake();
 					} else if op == Op::CreditUpdate {
-						debug!("CrediteUpdate currently not supported: {:?}", header);
+						// peer_buf_alloc/peer_fwd_cnt and the tx_waker wake-up
+						// were already handled above; this arm only exists to
+						// keep the packet from falling into the catch-all below.
 					} else if op == Op::Shutdown {
 						raw.state = VsockState::Shutdown;
 					} else {
 						hdr = Some(*header);
 						if op == Op::CreditRequest {
-							fwd_cnt = Some(raw.buffer.len().try_into().unwrap());
+							fwd_cnt = Some(raw.rx_cnt);
 						}
 					}
 				}
@@ -157,6 +268,235 @@ async fn vsock_run() {
 	.await
 }
 
+/// Send `data` to the peer connected on `port`, honouring its currently
+/// advertised credit window (`peer_free`).
+///
+/// `Type::Stream` sends are capped to `peer_free`, so a short result means
+/// the caller should retry with the remainder. `Type::SeqPacket` sends
+/// preserve the datagram boundary instead: `data` is only ever sent as a
+/// single `Op::Rw` packet, so if the whole of it doesn't currently fit in
+/// the peer's window, the send waits for the full window rather than
+/// emitting a partial record.
+///
+/// Either way, if the window is exhausted the caller's waker is parked in
+/// `tx_waker` and woken once an `Op::CreditUpdate` (or any other header
+/// carrying fresh credit) arrives.
+pub(crate) async fn send(port: u32, data: &[u8]) -> io::Result<usize> {
+	future::poll_fn(|cx| {
+		let Some(driver) = hardware::get_vsock_driver() else {
+			return Poll::Ready(Err(EIO));
+		};
+
+		let mut vsock_guard = VSOCK_MAP.lock();
+		let Some(raw) = vsock_guard.get_mut_socket(port) else {
+			return Poll::Ready(Err(io::Error::EINVAL));
+		};
+		if raw.state != VsockState::Connected {
+			return Poll::Ready(Err(io::Error::ENOTCONN));
+		}
+
+		let peer_free = raw.peer_free();
+		let must_fit_whole = raw.socket_type == Type::SeqPacket;
+		if peer_free == 0 || (must_fit_whole && (peer_free as usize) < data.len()) {
+			raw.tx_waker.register(cx.waker());
+			return Poll::Pending;
+		}
+
+		let len = data.len().min(peer_free as usize);
+		let local_cid = raw.local_cid;
+		let remote_cid = raw.remote_cid;
+		let remote_port = raw.remote_port;
+		let socket_type = raw.socket_type;
+		raw.tx_cnt = raw.tx_cnt.wrapping_add(len.try_into().unwrap());
+		let rx_cnt = raw.rx_cnt;
+		drop(vsock_guard);
+
+		const HEADER_SIZE: usize = core::mem::size_of::<Hdr>();
+		let mut driver_guard = driver.lock();
+		driver_guard.send_packet(HEADER_SIZE + len, |buffer| {
+			let (header, payload) = buffer.split_at_mut(HEADER_SIZE);
+			let response = unsafe { &mut *(header.as_mut_ptr() as *mut Hdr) };
+
+			response.src_cid = le64::from_ne(local_cid.into());
+			response.dst_cid = le64::from_ne(remote_cid.into());
+			response.src_port = le32::from_ne(port);
+			response.dst_port = le32::from_ne(remote_port);
+			response.len = le32::from_ne(len.try_into().unwrap());
+			response.type_ = le16::from_ne(socket_type.into());
+			response.op = le16::from_ne(Op::Rw.into());
+			response.flags = le32::from_ne(0);
+			response.buf_alloc = le32::from_ne(RAW_SOCKET_BUFFER_SIZE as u32);
+			response.fwd_cnt = le32::from_ne(rx_cnt);
+			payload.copy_from_slice(&data[..len]);
+		});
+
+		Poll::Ready(Ok(len))
+	})
+	.await
+}
+
+/// Notify the peer connected on `port` that we have freed up receive buffer
+/// space, by sending it an `Op::CreditUpdate` carrying our current
+/// `fwd_cnt`/`buf_alloc`. Call this after the application has drained bytes
+/// out of a socket's `buffer`, so a peer stalled on our credit window can
+/// resume sending.
+pub(crate) fn send_credit_update(port: u32) {
+	let Some(driver) = hardware::get_vsock_driver() else {
+		return;
+	};
+
+	let vsock_guard = VSOCK_MAP.lock();
+	let Some(raw) = vsock_guard.get_socket(port) else {
+		return;
+	};
+	if raw.state != VsockState::Connected {
+		return;
+	}
+	let local_cid = raw.local_cid;
+	let remote_cid = raw.remote_cid;
+	let remote_port = raw.remote_port;
+	let socket_type = raw.socket_type;
+	let rx_cnt = raw.rx_cnt;
+	drop(vsock_guard);
+
+	const HEADER_SIZE: usize = core::mem::size_of::<Hdr>();
+	let mut driver_guard = driver.lock();
+	driver_guard.send_packet(HEADER_SIZE, |buffer| {
+		let response = unsafe { &mut *(buffer.as_mut_ptr() as *mut Hdr) };
+
+		response.src_cid = le64::from_ne(local_cid.into());
+		response.dst_cid = le64::from_ne(remote_cid.into());
+		response.src_port = le32::from_ne(port);
+		response.dst_port = le32::from_ne(remote_port);
+		response.len = le32::from_ne(0);
+		response.type_ = le16::from_ne(socket_type.into());
+		response.op = le16::from_ne(Op::CreditUpdate.into());
+		response.flags = le32::from_ne(0);
+		response.buf_alloc = le32::from_ne(RAW_SOCKET_BUFFER_SIZE as u32);
+		response.fwd_cnt = le32::from_ne(rx_cnt);
+	});
+}
+
+/// Drain up to `buf.len()` bytes out of the `Type::Stream` socket bound on
+/// `port`, into `buf`. Acknowledges the drained bytes via
+/// [`RawSocket::ack_read`] and, if any were read, notifies the peer with
+/// [`send_credit_update`] so it can resume sending once its credit window
+/// allows.
+pub(crate) fn recv(port: u32, buf: &mut [u8]) -> io::Result<usize> {
+	let mut vsock_guard = VSOCK_MAP.lock();
+	let Some(raw) = vsock_guard.get_mut_socket(port) else {
+		return Err(io::Error::EINVAL);
+	};
+	let RecvBuffer::Stream(data) = &mut raw.buffer else {
+		return Err(io::Error::EINVAL);
+	};
+
+	let len = buf.len().min(data.len());
+	buf[..len].copy_from_slice(&data[..len]);
+	data.drain(..len);
+	raw.ack_read(len);
+	drop(vsock_guard);
+
+	if len > 0 {
+		send_credit_update(port);
+	}
+
+	Ok(len)
+}
+
+/// Pop a single framed datagram out of the `Type::SeqPacket` socket bound on
+/// `port`, copying up to `buf.len()` bytes of it into `buf`; any remainder
+/// is discarded, as for a standard `SOCK_SEQPACKET` read into an undersized
+/// buffer. Returns `Ok(None)` if no datagram is queued yet.
+///
+/// Like [`recv`], the whole record is acknowledged via [`RawSocket::ack_read`]
+/// and the peer is notified with [`send_credit_update`] so it can resume
+/// sending.
+pub(crate) fn recv_seqpacket(port: u32, buf: &mut [u8]) -> io::Result<Option<usize>> {
+	let mut vsock_guard = VSOCK_MAP.lock();
+	let Some(raw) = vsock_guard.get_mut_socket(port) else {
+		return Err(io::Error::EINVAL);
+	};
+	let RecvBuffer::SeqPacket(records) = &mut raw.buffer else {
+		return Err(io::Error::EINVAL);
+	};
+
+	let Some(record) = records.pop_front() else {
+		return Ok(None);
+	};
+
+	let len = buf.len().min(record.len());
+	buf[..len].copy_from_slice(&record[..len]);
+	raw.ack_read(record.len());
+	drop(vsock_guard);
+
+	send_credit_update(port);
+
+	Ok(Some(len))
+}
+
+/// First port handed out to outbound connections. Ports below this are left
+/// free for well-known / application-chosen listen ports.
+const EPHEMERAL_PORT_START: u32 = 1024;
+
+/// Establish an outbound (client-side) connection to `remote_cid:remote_port`.
+///
+/// Allocates a local ephemeral port, sends an `Op::Request` through the
+/// driver and awaits the peer's answer: `Op::Response` completes the
+/// connection, while `Op::Rst` (or an `Op::Response` of a different socket
+/// type) fails it with `ECONNREFUSED`. Returns `EIO` if no vsock driver is
+/// present when the call is made.
+pub(crate) async fn connect(
+	remote_cid: u32,
+	remote_port: u32,
+	socket_type: Type,
+) -> io::Result<u32> {
+	let Some(driver) = hardware::get_vsock_driver() else {
+		return Err(EIO);
+	};
+	let local_cid = driver.lock().guest_cid();
+
+	let port = VSOCK_MAP
+		.lock()
+		.connect(local_cid, remote_cid, remote_port, socket_type)?;
+
+	const HEADER_SIZE: usize = core::mem::size_of::<Hdr>();
+	driver.lock().send_packet(HEADER_SIZE, |buffer| {
+		let request = unsafe { &mut *(buffer.as_mut_ptr() as *mut Hdr) };
+
+		request.src_cid = le64::from_ne(local_cid.into());
+		request.dst_cid = le64::from_ne(remote_cid.into());
+		request.src_port = le32::from_ne(port);
+		request.dst_port = le32::from_ne(remote_port);
+		request.len = le32::from_ne(0);
+		request.type_ = le16::from_ne(socket_type.into());
+		request.op = le16::from_ne(Op::Request.into());
+		request.flags = le32::from_ne(0);
+		request.buf_alloc = le32::from_ne(RAW_SOCKET_BUFFER_SIZE as u32);
+		request.fwd_cnt = le32::from_ne(0);
+	});
+
+	future::poll_fn(|cx| {
+		let mut vsock_guard = VSOCK_MAP.lock();
+		let Some(raw) = vsock_guard.get_mut_socket(port) else {
+			return Poll::Ready(Err(ECONNREFUSED));
+		};
+
+		match raw.state {
+			VsockState::Connected => Poll::Ready(Ok(port)),
+			VsockState::Connecting => {
+				raw.waker.register(cx.waker());
+				Poll::Pending
+			}
+			_ => {
+				vsock_guard.remove_socket(port);
+				Poll::Ready(Err(ECONNREFUSED))
+			}
+		}
+	})
+	.await
+}
+
 pub(crate) struct VsockMap {
 	port_map: BTreeMap<u32, RawSocket>,
 }
@@ -168,13 +508,36 @@ impl VsockMap {
 		}
 	}
 
-	pub fn bind(&mut self, port: u32) -> io::Result<()> {
+	pub fn bind(&mut self, port: u32, socket_type: Type) -> io::Result<()> {
 		self.port_map
-			.try_insert(port, RawSocket::new(VsockState::Listen))
+			.try_insert(port, RawSocket::new(VsockState::Listen, socket_type))
 			.map_err(|_| EADDRINUSE)?;
 		Ok(())
 	}
 
+	/// Allocate a socket in `VsockState::Connecting` on a free local port,
+	/// for an outbound connection to `remote_cid:remote_port`.
+	fn connect(
+		&mut self,
+		local_cid: u32,
+		remote_cid: u32,
+		remote_port: u32,
+		socket_type: Type,
+	) -> io::Result<u32> {
+		let port = (EPHEMERAL_PORT_START..=u32::MAX)
+			.find(|port| !self.port_map.contains_key(port))
+			.ok_or(EADDRINUSE)?;
+
+		let mut raw = RawSocket::new(VsockState::Connecting, socket_type);
+		raw.local_cid = local_cid;
+		raw.remote_cid = remote_cid;
+		raw.remote_port = remote_port;
+		self.port_map
+			.try_insert(port, raw)
+			.map_err(|_| EADDRINUSE)?;
+		Ok(port)
+	}
+
 	pub fn get_socket(&self, port: u32) -> Option<&RawSocket> {
 		self.port_map.get(&port)
 	}